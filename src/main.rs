@@ -1,12 +1,38 @@
-//! Reading temperature from a TMP36 sensor every second    
-//! 
-//! Averaging of N-X ADC samples for accurate conversion: 
+//! Reading temperature from a TMP36 sensor ADC_HZ (15) times a second
+//!
+//! Averaging of N-X ADC samples for accurate conversion:
 //! - voltage is sampled 12 times, then sorted, the two biggest and two smallest values are dropped
-//! - final sample is obtained by averaging the remaining 8 values 
-//! 
+//! - final sample is obtained by averaging the remaining 8 values
+//!
 //! Stable display value:
-//! - display a moving average of last 8 samples
-//! 
+//! - smooth the trimmed-mean samples with an exponential moving average (see ALPHA),
+//!   seeded with the first raw sample so there is no ramp-from-zero at boot
+//!
+//! VDDA self-calibration:
+//! - the internal VREFINT channel (ADC1 channel 17) is sampled alongside the TMP36
+//! - combined with the factory-trimmed VREFINT_CAL word, this gives the true supply
+//!   rail instead of assuming a fixed 3.3 V, so readings stay accurate on noisy or
+//!   battery-backed supplies
+//!
+//! MCU die temperature:
+//! - the internal temperature sensor (ADC1 channel 18) is sampled alongside VREFINT
+//! - the factory TS_CAL1/TS_CAL2 points give a linear mapping from raw ADC counts to
+//!   degrees Celsius, displayed as a third line so the TMP36 reading can be checked
+//!   against the die for self-heating
+//!
+//! Thermostat:
+//! - a configurable setpoint drives a GPIOB relay output with bang-bang hysteresis
+//! - the relay turns on when the measured temperature drops HYST below the setpoint
+//!   and off when it rises HYST above it, leaving it unchanged in the deadband in
+//!   between so the relay doesn't chatter
+//!
+//! Sampling cadence:
+//! - TIM4 drives ADC acquisition and the averaging/control pipeline at ADC_HZ,
+//!   independently of the display - TIM3 only paces the OLED refresh at DISPLAY_HZ
+//! - a rolling history of averaged readings is kept so transients between refreshes
+//!   aren't lost, and the true (cumulative, never-resetting) session MIN_C/MAX_C is
+//!   shown on the display; the history buffer itself is not displayed
+//!
 
 
 #![no_std]
@@ -25,25 +51,28 @@ use core::cell::{Cell, RefCell};
 
 use stm32f4::stm32f411::interrupt;
 
+// `Vref`/`Temperature` marker types and the `enable_vref`/`enable_temperature` methods used
+// below are only present from stm32f4xx-hal 0.9 onwards - pin Cargo.toml accordingly, or these
+// internal-channel calls won't resolve.
 use crate::hal::{
-    i2c::I2c, 
-    prelude::*, 
-    gpio::{gpioa::PA4, Analog},
+    i2c::I2c,
+    prelude::*,
+    gpio::{gpioa::PA4, gpiob::PB0, Analog, Output, PushPull},
     stm32,
     delay::Delay,
-    adc::{Adc, config::{AdcConfig, SampleTime, Clock, Resolution}},
+    adc::{Adc, Vref, Temperature, config::{AdcConfig, SampleTime, Clock, Resolution}},
     timer::{Timer, Event},
     time::Hertz,
     stm32::Interrupt,
     };
 
 use ssd1306::{
-    prelude::*, 
+    prelude::*,
     Builder as SSD1306Builder
     };
 
 use embedded_graphics::{
-    fonts::{Font12x16, Text},
+    fonts::{Font12x16, Font6x8, Text},
     pixelcolor::BinaryColor,
     prelude::*,
     style::TextStyleBuilder,
@@ -51,21 +80,79 @@ use embedded_graphics::{
 
 use core::fmt;
 use arrayvec::ArrayString;
+use embedded_hal::digital::v2::OutputPin;
 
 // globally accessible values
 static TEMP_C: Mutex<Cell<i16>> = Mutex::new(Cell::new(0i16));
 static TEMP_F: Mutex<Cell<i16>> = Mutex::new(Cell::new(0i16));
-static BUF: Mutex<Cell<[u16;8]>> = Mutex::new(Cell::new([0u16;8]));
+static DIE_TEMP_C: Mutex<Cell<i16>> = Mutex::new(Cell::new(0i16));
 
-// interrupt and peripherals for ADC
-static TIMER_TIM3: Mutex<RefCell<Option<Timer<stm32::TIM3>>>> = Mutex::new(RefCell::new(None));
+// exponential moving average state for the display value, seeded on the first sample
+static AVG: Mutex<Cell<f32>> = Mutex::new(Cell::new(0.0));
+static AVG_SEEDED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+// thermostat setpoint, in tenths of a degree Celsius like TEMP_C, and the relay state
+static SETPOINT_C: Mutex<Cell<i16>> = Mutex::new(Cell::new(DEFAULT_SETPOINT_C));
+static HEATING: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+// rolling history of averaged readings (tenths of a degree, like TEMP_C), so fast
+// transients between display refreshes are kept around instead of only living in TEMP_C
+static HISTORY: Mutex<Cell<[i16; HISTORY_LEN]>> = Mutex::new(Cell::new([0i16; HISTORY_LEN]));
+static HISTORY_IDX: Mutex<Cell<usize>> = Mutex::new(Cell::new(0));
+
+// true session min/max, moved monotonically away from the sentinel extremes - NOT derived
+// from HISTORY, which is only a fixed-length window and would let old peaks age out
+static MIN_C: Mutex<Cell<i16>> = Mutex::new(Cell::new(i16::max_value()));
+static MAX_C: Mutex<Cell<i16>> = Mutex::new(Cell::new(i16::min_value()));
+
+// flag set by TIM3 and consumed by the main loop to pace the display refresh
+static DISPLAY_TICK: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+// interrupt and peripherals for ADC, sampled from TIM4
+static TIMER_TIM4: Mutex<RefCell<Option<Timer<stm32::TIM4>>>> = Mutex::new(RefCell::new(None));
 static GADC: Mutex<RefCell<Option<Adc<stm32::ADC1>>>> = Mutex::new(RefCell::new(None));
 static ANALOG: Mutex<RefCell<Option<PA4<Analog>>>> = Mutex::new(RefCell::new(None));
+static VREF: Mutex<RefCell<Option<Vref>>> = Mutex::new(RefCell::new(None));
+static DIE_TEMP_SENSOR: Mutex<RefCell<Option<Temperature>>> = Mutex::new(RefCell::new(None));
+static RELAY: Mutex<RefCell<Option<PB0<Output<PushPull>>>>> = Mutex::new(RefCell::new(None));
+
+// TIM3 now only paces the display, it no longer touches the ADC
+static TIMER_TIM3: Mutex<RefCell<Option<Timer<stm32::TIM3>>>> = Mutex::new(RefCell::new(None));
+
+// factory VREFINT calibration word, read once at boot (see VREFINT_CAL_ADDR below)
+static VREFINT_CAL: Mutex<Cell<u16>> = Mutex::new(Cell::new(0u16));
 
-const FACTOR: f32 = 3300.0/4096.0; //3300 mV / 4096 values for 12-bit ADC
+// factory die-temperature calibration points, read once at boot (see TS_CAL1/2_ADDR below)
+static TS_CAL1: Mutex<Cell<u16>> = Mutex::new(Cell::new(0u16));
+static TS_CAL2: Mutex<Cell<u16>> = Mutex::new(Cell::new(0u16));
 
 const BOOT_DELAY_MS: u16 = 100; //delay for the I2C to start correctly after power up
 
+// address of the factory VREFINT calibration word, measured at VDDA = 3.3 V, T = 30 C
+// (see the STM32F411 reference manual, section "Temperature sensor and internal
+// reference voltage calibration")
+const VREFINT_CAL_ADDR: u32 = 0x1FFF_7A2A;
+
+const VREFINT_CAL_MV: f32 = 3300.0; //VDDA, in mV, at which VREFINT_CAL was measured
+
+// addresses of the factory die-temperature calibration points, both measured at VDDA = 3.3 V
+// (see the STM32F411 reference manual, section "Temperature sensor calibration")
+const TS_CAL1_ADDR: u32 = 0x1FFF_7A2C; //raw ADC reading at 30 C
+const TS_CAL2_ADDR: u32 = 0x1FFF_7A2E; //raw ADC reading at 110 C
+
+const TS_CAL1_TEMP_C: f32 = 30.0;
+const TS_CAL2_TEMP_C: f32 = 110.0;
+
+const DEFAULT_SETPOINT_C: i16 = 220; //22.0 C, in tenths of a degree like TEMP_C
+const HYST: i16 = 5; //0.5 C hysteresis band around the setpoint, in tenths of a degree
+
+const ALPHA: f32 = 0.1; //EMA smoothing factor - lower is smoother but slower to react
+
+const ADC_HZ: u32 = 15; //TIM4 ADC acquisition rate, decoupled from the display refresh
+const DISPLAY_HZ: u32 = 5; //TIM3 display refresh rate (was a fixed 200 ms delay)
+
+const HISTORY_LEN: usize = ADC_HZ as usize * 4; //a few seconds of averaged readings
+
 #[entry]
 fn main() -> ! {
     if let (Some(dp), Some(cp)) = (
@@ -73,26 +160,46 @@ fn main() -> ! {
         cortex_m::peripheral::Peripherals::take(),
 ) {
         // Set up the system clock. Speed is not important in this case
-        
+
         let rcc = dp.RCC.constrain();
         let clocks = rcc.cfgr.sysclk(25.mhz()).freeze();
-        
+
         let mut delay = Delay::new(cp.SYST, clocks);
-        
+
         //delay necessary for the I2C to initiate correctly and start on boot without having to reset the board
         delay.delay_ms(BOOT_DELAY_MS);
 
+        // read the factory VREFINT calibration word once, it never changes at runtime
+        let vrefint_cal = unsafe { core::ptr::read_volatile(VREFINT_CAL_ADDR as *const u16) };
+        free(|cs| VREFINT_CAL.borrow(cs).replace(vrefint_cal));
+
+        // read the factory die-temperature calibration points once, they never change at runtime
+        let ts_cal1 = unsafe { core::ptr::read_volatile(TS_CAL1_ADDR as *const u16) };
+        let ts_cal2 = unsafe { core::ptr::read_volatile(TS_CAL2_ADDR as *const u16) };
+        free(|cs| {
+            TS_CAL1.borrow(cs).replace(ts_cal1);
+            TS_CAL2.borrow(cs).replace(ts_cal2);
+        });
+
         //set up ADC
         let gpioa = dp.GPIOA.split();
         let adcconfig = AdcConfig::default().clock(Clock::Pclk2_div_8).resolution(Resolution::Twelve);
-        let adc = Adc::adc1(dp.ADC1, true, adcconfig);
-                
+        let mut adc = Adc::adc1(dp.ADC1, true, adcconfig);
+
         let pa4 = gpioa.pa4.into_analog();
 
-        // move the PA4 pin and the ADC into the 'global storage'
+        // enable the internal VREFINT channel (ADC1 channel 17) used to self-calibrate VDDA
+        let vref = adc.enable_vref(&mut delay);
+
+        // enable the internal temperature sensor channel (ADC1 channel 18)
+        let die_temp_sensor = adc.enable_temperature(&mut delay);
+
+        // move the PA4 pin, the VREFINT and temperature channels and the ADC into the 'global storage'
         free(|cs| {
-            *GADC.borrow(cs).borrow_mut() = Some(adc);        
-            *ANALOG.borrow(cs).borrow_mut() = Some(pa4);            
+            *GADC.borrow(cs).borrow_mut() = Some(adc);
+            *ANALOG.borrow(cs).borrow_mut() = Some(pa4);
+            *VREF.borrow(cs).borrow_mut() = Some(vref);
+            *DIE_TEMP_SENSOR.borrow(cs).borrow_mut() = Some(die_temp_sensor);
         });
 
         // Set up I2C - SCL is PB8 and SDA is PB9; they are set to Alternate Function 4
@@ -101,54 +208,93 @@ fn main() -> ! {
         let sda = gpiob.pb9.into_alternate_af4().set_open_drain();
         let i2c = I2c::i2c1(dp.I2C1, (scl, sda), 400.khz(), clocks);
 
-        // Set up the display
-        let mut disp: GraphicsMode<_> = SSD1306Builder::new().size(DisplaySize::Display128x32).connect_i2c(i2c).into();
+        // thermostat relay output - PB0, push-pull, starts off
+        let relay = gpiob.pb0.into_push_pull_output();
+        free(|cs| {
+            *RELAY.borrow(cs).borrow_mut() = Some(relay);
+        });
+
+        // Set up the display - bumped to 128x64 so the die-temp, setpoint and min/max
+        // lines added below the original two readings actually have room to render
+        let mut disp: GraphicsMode<_> = SSD1306Builder::new().size(DisplaySize::Display128x64).connect_i2c(i2c).into();
         disp.init().unwrap();
 
-        // set up timer and interrupts
-        let mut adctimer = Timer::tim3(dp.TIM3, Hertz(1), clocks); //interrupt will fire every second
+        // set up timers and interrupts - TIM4 samples the ADC, TIM3 paces the display
+        let mut adctimer = Timer::tim4(dp.TIM4, Hertz(ADC_HZ), clocks);
         adctimer.listen(Event::TimeOut);
-                
+
+        let mut displaytimer = Timer::tim3(dp.TIM3, Hertz(DISPLAY_HZ), clocks);
+        displaytimer.listen(Event::TimeOut);
+
         free(|cs| {
-            TIMER_TIM3.borrow(cs).replace(Some(adctimer));
+            TIMER_TIM4.borrow(cs).replace(Some(adctimer));
+            TIMER_TIM3.borrow(cs).replace(Some(displaytimer));
             });
 
         let mut nvic = cp.NVIC;
-            unsafe {            
-                nvic.set_priority(Interrupt::TIM3, 1);
+            unsafe {
+                nvic.set_priority(Interrupt::TIM4, 1);
+                cortex_m::peripheral::NVIC::unmask(Interrupt::TIM4);
+
+                nvic.set_priority(Interrupt::TIM3, 2);
                 cortex_m::peripheral::NVIC::unmask(Interrupt::TIM3);
             }
-                        
+
+            cortex_m::peripheral::NVIC::unpend(Interrupt::TIM4);
             cortex_m::peripheral::NVIC::unpend(Interrupt::TIM3);
 
-        //set up text style for the display
+        //set up text styles for the display - the big font for the three temperature
+        //readings, a small one for the status lines packed into the last 16 px row
         let text_style = TextStyleBuilder::new(Font12x16).text_color(BinaryColor::On).build();
+        let small_text_style = TextStyleBuilder::new(Font6x8).text_color(BinaryColor::On).build();
 
         loop {
-                        
+
             let mut buf_temp_c = ArrayString::<[u8; 8]>::new(); //buffer for the temperature reading
             let mut buf_temp_f = ArrayString::<[u8; 8]>::new(); //buffer for the temperature reading
-        
-            //clean up the display    
+            let mut buf_die_temp_c = ArrayString::<[u8; 8]>::new(); //buffer for the die temperature reading
+            let mut buf_setpoint_c = ArrayString::<[u8; 8]>::new(); //buffer for the thermostat setpoint
+            let mut buf_minmax_c = ArrayString::<[u8; 24]>::new(); //buffer for the session min/max
+
+            //wait for TIM3 to signal a display tick instead of a fixed delay, so the
+            //refresh rate is set by DISPLAY_HZ rather than hard-coded here
+            while !free(|cs| DISPLAY_TICK.borrow(cs).replace(false)) {}
+
+            //clean up the display
             for x in 0..96 {
-                for y in 0..32 {
+                for y in 0..64 {
                     disp.set_pixel(x,y,0);
                 }
             }
 
             let celsius = free(|cs| TEMP_C.borrow(cs).get()); //get the current temperature in Celsius
             let fahrenheit = free(|cs| TEMP_F.borrow(cs).get()); //get the current temperature in Fahrenheit
-            
+            let die_celsius = free(|cs| DIE_TEMP_C.borrow(cs).get()); //get the current MCU die temperature in Celsius
+            let setpoint = free(|cs| SETPOINT_C.borrow(cs).get()); //get the current thermostat setpoint
+            let heating = free(|cs| HEATING.borrow(cs).get()); //get the current relay state
+
             formatter(&mut buf_temp_c, celsius, 67 as char); // 67 is "C" in ASCII
             Text::new(buf_temp_c.as_str(), Point::new(0, 0)).into_styled(text_style).draw(&mut disp);
 
             formatter(&mut buf_temp_f, fahrenheit, 70 as char); // 70 is "F" in ASCII
             Text::new(buf_temp_f.as_str(), Point::new(0, 16)).into_styled(text_style).draw(&mut disp);
 
+            formatter(&mut buf_die_temp_c, die_celsius, 67 as char); // 67 is "C" in ASCII
+            Text::new(buf_die_temp_c.as_str(), Point::new(0, 32)).into_styled(text_style).draw(&mut disp);
+
+            //"H" when the relay is heating, "-" when it's idle, next to the setpoint;
+            //drawn in the small font so it fits, alongside min/max, in the last 16 px row
+            let heating_flag = if heating { 72 as char } else { 45 as char }; // 72 is "H" in ASCII
+            formatter(&mut buf_setpoint_c, setpoint, heating_flag);
+            Text::new(buf_setpoint_c.as_str(), Point::new(0, 48)).into_styled(small_text_style).draw(&mut disp);
+
+            let min_c = free(|cs| MIN_C.borrow(cs).get()); //true session minimum
+            let max_c = free(|cs| MAX_C.borrow(cs).get()); //true session maximum
+            minmax_formatter(&mut buf_minmax_c, min_c, max_c);
+            Text::new(buf_minmax_c.as_str(), Point::new(0, 56)).into_styled(small_text_style).draw(&mut disp);
+
             disp.flush().unwrap();
-            
-            delay.delay_ms(200_u16); //update the display every 200 ms
-            
+
             }
 
         }
@@ -158,23 +304,44 @@ fn main() -> ! {
 
 #[interrupt]
 
-// read from ADC on pin PA4 and update the global values every second
+// read from ADC on pin PA4 and update the global values at ADC_HZ
+
+fn TIM4() {
 
-fn TIM3() {
-        
     free(|cs| {
-        stm32::NVIC::unpend(Interrupt::TIM3);
-        if let (Some(ref mut tim3), Some(ref mut adc), Some(ref mut analog)) = (
-        TIMER_TIM3.borrow(cs).borrow_mut().deref_mut(),
+        stm32::NVIC::unpend(Interrupt::TIM4);
+        if let (Some(ref mut tim4), Some(ref mut adc), Some(ref mut analog), Some(ref mut vref), Some(ref mut die_temp_sensor), Some(ref mut relay)) = (
+        TIMER_TIM4.borrow(cs).borrow_mut().deref_mut(),
         GADC.borrow(cs).borrow_mut().deref_mut(),
-        
-        ANALOG.borrow(cs).borrow_mut().deref_mut())
-        
+
+        ANALOG.borrow(cs).borrow_mut().deref_mut(),
+        VREF.borrow(cs).borrow_mut().deref_mut(),
+        DIE_TEMP_SENSOR.borrow(cs).borrow_mut().deref_mut(),
+        RELAY.borrow(cs).borrow_mut().deref_mut())
+
         {
-            tim3.clear_interrupt(Event::TimeOut);
-                        
+            tim4.clear_interrupt(Event::TimeOut);
+
+            // sample VREFINT and derive the true VDDA for this cycle instead of assuming 3.3 V
+            let vrefint_raw = adc.convert(vref, SampleTime::Cycles_480);
+            let vrefint_cal = VREFINT_CAL.borrow(cs).get();
+            let vdda_mv = VREFINT_CAL_MV * vrefint_cal as f32 / vrefint_raw as f32;
+            let factor = vdda_mv / 4096.0; //mV per ADC count for a 12-bit conversion at this VDDA
+
+            // sample the MCU die temperature sensor and apply the factory two-point calibration
+            let die_temp_raw = adc.convert(die_temp_sensor, SampleTime::Cycles_480);
+            let ts_cal1 = TS_CAL1.borrow(cs).get();
+            let ts_cal2 = TS_CAL2.borrow(cs).get();
+            // TS_CAL1/TS_CAL2 were measured at VDDA = 3.3 V; rescale the raw reading to
+            // what it would have been at that reference before interpolating, so a
+            // drooping supply doesn't also skew the die temperature
+            let die_temp_raw_scaled = die_temp_raw as f32 * (vdda_mv / VREFINT_CAL_MV);
+            let die_celsius = TS_CAL1_TEMP_C
+                + (TS_CAL2_TEMP_C - TS_CAL1_TEMP_C) * (die_temp_raw_scaled - ts_cal1 as f32) / (ts_cal2 as f32 - ts_cal1 as f32);
+            DIE_TEMP_C.borrow(cs).replace((die_celsius * 10.0) as i16);
+
             //sample the temperature from the TMP36 sensor 12 times
-            let mut adc_buf: [u16;12] = [0u16;12]; 
+            let mut adc_buf: [u16;12] = [0u16;12];
 
             for n in 0..12 {
                 let sample = adc.convert(analog, SampleTime::Cycles_144);
@@ -182,31 +349,35 @@ fn TIM3() {
             }
 
             //sort the buffer and drop the four most dispersed values
-            sort(&mut adc_buf); 
+            sort(&mut adc_buf);
 
             let mut adc_buf_trimmed: [u16;8] = [0u16;8];
             for k in 0..8 {
                 adc_buf_trimmed[k] = adc_buf[k+2];
             }
-            
+
             //average the remaining 8 values
             let sample = average(&adc_buf_trimmed);
 
-            //update the global buffer with the new sample
-            let buf = BUF.borrow(cs).get();
-            let new_buf = circular(&buf, sample);
-            BUF.borrow(cs).replace(new_buf);
+            //smooth the trimmed-mean sample with an exponential moving average;
+            //seed it with the first raw sample instead of ramping up from zero
+            let avg_sample_f = if AVG_SEEDED.borrow(cs).get() {
+                let avg = AVG.borrow(cs).get();
+                avg * (1.0 - ALPHA) + sample as f32 * ALPHA
+            } else {
+                AVG_SEEDED.borrow(cs).replace(true);
+                sample as f32
+            };
+            AVG.borrow(cs).replace(avg_sample_f);
+            let avg_sample = avg_sample_f as u16;
 
-             //get the average of the current global buffer
-            let avg_sample = average(&new_buf);
-            
             //ADC reading converted to milivolts, then to Celsius degrees
             //the common formula is (milivolts - 500) / 10
             //10mV per Celsius degree with 500 mV offset
 
-            let voltage = avg_sample as f32 * FACTOR; 
+            let voltage = avg_sample as f32 * factor;
 
-            let celsius = (voltage - 500.0) / 10.0; 
+            let celsius = (voltage - 500.0) / 10.0;
 
             let mut fahrenheit = celsius * 9.0;
             fahrenheit /= 5.0;
@@ -215,40 +386,87 @@ fn TIM3() {
             //as we want to get the tenths of the degree and display them easily
             //we multiply the results by 10
 
-            TEMP_C.borrow(cs).replace((celsius * 10.0) as i16);
+            let temp = (celsius * 10.0) as i16;
+            TEMP_C.borrow(cs).replace(temp);
             TEMP_F.borrow(cs).replace((fahrenheit * 10.0) as i16);
+
+            //push into the rolling history so recent transients survive between refreshes
+            let idx = HISTORY_IDX.borrow(cs).get();
+            let mut history = HISTORY.borrow(cs).get();
+            history[idx] = temp;
+            HISTORY.borrow(cs).replace(history);
+            HISTORY_IDX.borrow(cs).replace((idx + 1) % HISTORY_LEN);
+
+            //track the true session min/max; these only ever widen to take in `temp`
+            if temp < MIN_C.borrow(cs).get() {
+                MIN_C.borrow(cs).replace(temp);
+            }
+            if temp > MAX_C.borrow(cs).get() {
+                MAX_C.borrow(cs).replace(temp);
+            }
+
+            //bang-bang control with hysteresis: flip state at the band edges, hold inside it
+            let setpoint = SETPOINT_C.borrow(cs).get();
+            let mut heating = HEATING.borrow(cs).get();
+            if temp <= setpoint - HYST {
+                heating = true;
+            } else if temp >= setpoint + HYST {
+                heating = false;
+            }
+            HEATING.borrow(cs).replace(heating);
+
+            if heating {
+                relay.set_high().unwrap();
+            } else {
+                relay.set_low().unwrap();
+            }
         }
     });
 }
 
+#[interrupt]
 
-fn formatter(buf: &mut ArrayString<[u8; 8]>, val: i16, unit: char) {   
-    // helper function for the display    
+// paces the display refresh at DISPLAY_HZ, independently of the TIM4 ADC rate
+
+fn TIM3() {
+
+    free(|cs| {
+        stm32::NVIC::unpend(Interrupt::TIM3);
+        if let Some(ref mut tim3) = TIMER_TIM3.borrow(cs).borrow_mut().deref_mut() {
+            tim3.clear_interrupt(Event::TimeOut);
+            DISPLAY_TICK.borrow(cs).replace(true);
+        }
+    });
+}
+
+
+fn formatter(buf: &mut ArrayString<[u8; 8]>, val: i16, unit: char) {
+    // helper function for the display
     // takes a mutable text buffer, value and unit symbol as arguments
     // default sign is + (43 in ASCII)
     // in order to correctly handle negative values, their sign has to be reversed before splitting into digits
-    
-    let mut sign: char = 43 as char; 
-    
+
+    let mut sign: char = 43 as char;
+
     if val < 0 {
         sign = 45 as char;
     };
-    
+
     let mut new_val = val;
     if val < 0 {
-        new_val *= -1; 
+        new_val *= -1;
     }
 
     let tenths = new_val%10;
     let singles = (new_val/10)%10;
     let tens = (new_val/100)%10;
     let hundreds = (new_val/1000)%10;
-    
+
     //correctly handle values with only one or two digits, e.g. +100.5 F, -23.4 C, +7.5 F
 
     if (hundreds == 0) && (tens == 0) {
         fmt::write(buf, format_args!("{}  {}.{} {}", sign, singles as u8, tenths as u8, unit)).unwrap();
-    } 
+    }
     else if hundreds == 0 {
         fmt::write(buf, format_args!("{} {}{}.{} {}", sign, tens as u8, singles as u8, tenths as u8, unit)).unwrap();
     }
@@ -259,15 +477,18 @@ fn formatter(buf: &mut ArrayString<[u8; 8]>, val: i16, unit: char) {
 }
 
 
-fn circular(buf: &[u16;8], val: u16) -> [u16;8] {
+fn minmax_formatter(buf: &mut ArrayString<[u8; 24]>, min: i16, max: i16) {
+    // compact "session min/max" line for the display, e.g. "-0.5/+24.7 C"
+    // the sign is reconstructed explicitly, like in formatter(), since e.g. -5/10 == 0
+    // would otherwise silently drop the sign on small negative values
 
-    //simple circular buffer, first in first out
-    let mut new_buf: [u16;8] = [0u16;8];
-    for i in 0..7 {
-        new_buf[i] = buf[i+1];
-    }
-    new_buf[7] = val;
-    return new_buf
+    let min_sign: char = if min < 0 { 45 as char } else { 43 as char };
+    let max_sign: char = if max < 0 { 45 as char } else { 43 as char };
+
+    let min_abs = if min < 0 { -min } else { min };
+    let max_abs = if max < 0 { -max } else { max };
+
+    fmt::write(buf, format_args!("{}{}.{}/{}{}.{} C", min_sign, min_abs/10, min_abs%10, max_sign, max_abs/10, max_abs%10)).unwrap();
 }
 
 
@@ -285,7 +506,7 @@ fn average(buf: &[u16;8]) -> u16 {
 //from STM Application Note AN4073 "How to improve ADC accuracy"
 
 fn sort(arr: &mut [u16;12]) {
-    
+
     let mut exchange: u8 = 1;
     let mut tmp: u16 = 0;
 
@@ -299,6 +520,6 @@ fn sort(arr: &mut [u16;12]) {
                 exchange = 1;
             }
         }
-        
+
     }
-}
\ No newline at end of file
+}